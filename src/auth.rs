@@ -1,20 +1,34 @@
 use std::{sync::Arc, time::Duration};
 
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use libunftp::auth::{AuthenticationError, Authenticator as LibAuthenticator};
 use serde::Deserialize;
 use tokio::time::sleep;
 
-use crate::user::{User, UserMap};
+use crate::user::{User, UserMap, VirtualDir};
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct ClientCertCredential {
-    // pub allowed_cn: Option<String>,
+    pub allowed_cn: String,
 }
 
+/// Extracts the subject CN of the leaf certificate a client presented, if any.
+fn peer_cn(creds: &libunftp::auth::Credentials) -> Option<String> {
+    let leaf = creds.certificate_chain.as_ref()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(leaf.as_ref()).ok()?;
+    cert.subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_owned)
+}
+
+/// Authenticates against the live, hot-reloadable user set (see
+/// [`crate::config::watch`]).
 #[derive(Clone, Debug)]
 pub struct Authenticator {
-    pub users: Arc<UserMap>,
+    pub users: Arc<ArcSwap<UserMap>>,
 }
 
 #[async_trait]
@@ -25,15 +39,17 @@ impl LibAuthenticator<User> for Authenticator {
         username: &str,
         creds: &libunftp::auth::Credentials,
     ) -> Result<User, AuthenticationError> {
-        let res = if let Some(user) = self.users.get(username) {
-            match &creds.password {
-                Some(ref given_password) =>
-                    if !user.password_ok(given_password) {
-                        Err(AuthenticationError::BadPassword)
-                    } else {
-                        Ok(user.clone())
-                    },
-                None => Err(AuthenticationError::BadPassword),
+        let res = if let Some(user) = self.users.load().get(username) {
+            let cn_ok = peer_cn(creds).is_some_and(|cn| user.cn_ok(&cn));
+            let password_ok = creds
+                .password
+                .as_ref()
+                .is_some_and(|given_password| user.password_ok(given_password));
+
+            if cn_ok || password_ok {
+                Ok(user.clone())
+            } else {
+                Err(AuthenticationError::BadPassword)
             }
         } else {
             Err(AuthenticationError::BadUser)
@@ -48,3 +64,68 @@ impl LibAuthenticator<User> for Authenticator {
 
     fn name(&self) -> &str { std::any::type_name::<Self>() }
 }
+
+/// Authenticates against the host's own account database (PAM / passwd)
+/// instead of `config.yaml`.
+#[derive(Clone, Debug, Default)]
+pub struct SystemAuthenticator;
+
+#[async_trait]
+impl LibAuthenticator<User> for SystemAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        creds: &libunftp::auth::Credentials,
+    ) -> Result<User, AuthenticationError> {
+        let res = self.try_authenticate(username, creds).await;
+
+        if res.is_err() {
+            sleep(Duration::from_millis(1500)).await;
+        }
+
+        res
+    }
+
+    fn name(&self) -> &str { std::any::type_name::<Self>() }
+}
+
+impl SystemAuthenticator {
+    async fn try_authenticate(
+        &self,
+        username: &str,
+        creds: &libunftp::auth::Credentials,
+    ) -> Result<User, AuthenticationError> {
+        let Some(password) = creds.password.clone() else {
+            return Err(AuthenticationError::BadPassword);
+        };
+        let username = username.to_owned();
+
+        tokio::task::spawn_blocking(move || system_login(&username, &password))
+            .await
+            .unwrap_or(Err(AuthenticationError::BadPassword))
+    }
+}
+
+/// Blocking: runs PAM's conversation, so callers must invoke this from
+/// `spawn_blocking`.
+fn system_login(username: &str, password: &str) -> Result<User, AuthenticationError> {
+    let account =
+        uzers::get_user_by_name(username).ok_or(AuthenticationError::BadUser)?;
+
+    let mut client = pam::Client::with_password("ftp")
+        .map_err(|_| AuthenticationError::BadPassword)?;
+    client.conversation_mut().set_credentials(username, password);
+    client.authenticate().map_err(|_| AuthenticationError::BadPassword)?;
+
+    Ok(User::builder()
+        .name(username)
+        .access(
+            VirtualDir::builder()
+                .name("home")
+                .path(account.home_dir())
+                .uid(account.uid())
+                .gid(account.primary_group_id())
+                .build(),
+        )
+        .build())
+}
@@ -1,16 +1,103 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use log::{error, info};
+use serde::Deserialize;
 
 use crate::{
     user::{User, UserMap},
     BoxedStdError,
 };
 
-pub fn load(path: impl AsRef<Path>) -> Result<UserMap, BoxedStdError> {
+#[derive(Deserialize, Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    #[serde(default)]
+    pub required: bool,
+    /// When `true`, clients must present a certificate during the TLS
+    /// handshake for the connection to be accepted at all. When `false`
+    /// (the default), a certificate is merely requested, so CN-based logins
+    /// (see `User::cn_ok`) keep working alongside password-only users.
+    #[serde(default)]
+    pub client_cert_required: bool,
+}
+
+/// Selects how logins are authenticated.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackend {
+    #[default]
+    Yaml,
+    System,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct RawConfig {
+    #[serde(default)]
+    users: Vec<User>,
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    #[serde(default)]
+    auth: AuthBackend,
+}
+
+pub struct Config {
+    pub users: UserMap,
+    pub tls: Option<TlsConfig>,
+    pub auth: AuthBackend,
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<Config, BoxedStdError> {
     let content = fs::read_to_string(path)?;
 
-    let res: Vec<User> = serde_yaml::from_str(&content)?;
+    let res: RawConfig = serde_yaml::from_str(&content)?;
+
+    Ok(Config {
+        users: HashMap::from_iter(res.users.into_iter().map(|u| (u.name_cloned(), u))),
+        tls: res.tls,
+        auth: res.auth,
+    })
+}
+
+/// How often the config file's mtime is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Watches `path` for changes and atomically swaps the live user set on
+/// `users` when it is re-parsed successfully. Already-authenticated sessions
+/// keep their cloned `User`, so only new logins observe a reloaded config.
+/// On a parse error the previous map is kept and the error is logged.
+pub async fn watch(path: PathBuf, users: Arc<ArcSwap<UserMap>>) {
+    let mut last_modified = modified(&path);
+
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let current = modified(&path);
+        if current.is_none() || current == last_modified {
+            continue;
+        }
+
+        match load(&path) {
+            Ok(new_config) => {
+                users.store(Arc::new(new_config.users));
+                last_modified = current;
+                info!("Reloaded user config from {}", path.display());
+            },
+            Err(e) => error!(
+                "Failed to reload user config from {}: {e}, keeping previous users",
+                path.display()
+            ),
+        }
+    }
+}
 
-    Ok(HashMap::from_iter(
-        res.into_iter().map(|u| (u.name_cloned(), u)),
-    ))
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
 }
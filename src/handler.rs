@@ -1,15 +1,19 @@
 use std::{
     fmt::Debug,
     path::{Component, Path, PathBuf},
+    sync::Arc,
     time::SystemTime,
 };
 
 use async_trait::async_trait;
 use cfg_if::cfg_if;
+use dashmap::DashMap;
 use libunftp::storage::{Error, ErrorKind, Fileinfo, Metadata, Result, StorageBackend};
-use tokio::io::AsyncSeekExt;
+use md5::Md5;
+use sha3::{Digest, Sha3_256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
-use crate::user::User;
+use crate::user::{User, VirtualDir};
 
 cfg_if! {
     if #[cfg(target_os = "linux")] {
@@ -19,12 +23,31 @@ cfg_if! {
     }
 }
 
-#[derive(Debug)]
-pub struct Filesystem;
+/// Which digest a checksum was (or should be) computed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum ChecksumAlgorithm {
+    Md5,
+    Sha3_256,
+}
+
+type ChecksumKey = (PathBuf, u64, SystemTime, ChecksumAlgorithm);
+
+/// Cache of `(canonical_path, size, mtime, algorithm) -> hex digest`, shared
+/// by every session's [`Filesystem`] instance.
+pub type ChecksumCache = DashMap<ChecksumKey, String>;
+
+#[derive(Debug, Clone)]
+pub struct Filesystem {
+    checksums: Arc<ChecksumCache>,
+}
+
+impl Filesystem {
+    pub fn new(checksums: Arc<ChecksumCache>) -> Self { Filesystem { checksums } }
+}
 
 enum ResolveRes {
-    Read(PathBuf, PathBuf),
-    Write(PathBuf, PathBuf),
+    Read(PathBuf, Arc<VirtualDir>),
+    Write(PathBuf, Arc<VirtualDir>),
     Error(Error),
     Root(Vec<(String, PathBuf)>),
 }
@@ -70,12 +93,17 @@ impl ResolveRes {
     }
 
     fn write_ok(self) -> Result<PathBuf> {
+        self.write_ok_dir().map(|(w, _)| w)
+    }
+
+    /// Like [`Self::write_ok`], but also returns the resolved [`VirtualDir`].
+    fn write_ok_dir(self) -> Result<(PathBuf, Arc<VirtualDir>)> {
         match self {
             ResolveRes::Read(..) => Err(Error::new(
                 ErrorKind::PermissionDenied,
                 String::from("Error: permission denied"),
             )),
-            ResolveRes::Write(w, _) => Ok(w),
+            ResolveRes::Write(w, dir) => Ok((w, dir)),
             ResolveRes::Error(e) => Err(e),
             ResolveRes::Root(_) => Err(Error::new(
                 ErrorKind::PermissionDenied,
@@ -88,6 +116,20 @@ impl ResolveRes {
 #[derive(Debug)]
 pub struct Meta {
     inner: std::fs::Metadata,
+    /// Set for the synthetic `<dir>.tar` entry a directory is advertised under.
+    synthetic_tar: bool,
+}
+
+const TAR_SUFFIX: &str = ".tar";
+
+/// If `path`'s file name ends in [`TAR_SUFFIX`], returns the directory it would archive.
+fn tar_source(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let stem = name.strip_suffix(TAR_SUFFIX)?;
+    if stem.is_empty() {
+        return None;
+    }
+    Some(path.with_file_name(stem))
 }
 
 fn canonicalize<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
@@ -97,6 +139,84 @@ fn canonicalize<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
     Ok(p.as_path().to_path_buf())
 }
 
+#[cfg(unix)]
+async fn chown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        nix::unistd::chown(
+            &path,
+            uid.map(nix::unistd::Uid::from_raw),
+            gid.map(nix::unistd::Gid::from_raw),
+        )
+    })
+    .await
+    .map_err(|e| Error::new(ErrorKind::LocalError, e))?
+    .map_err(|e| Error::new(ErrorKind::LocalError, e))
+}
+
+/// Creates the directory at `path` and `chown`s it to `dir`'s uid/gid. A
+/// pre-existing directory is left untouched.
+#[cfg(unix)]
+async fn create_owned(path: PathBuf, dir: &VirtualDir) -> Result<()> {
+    match tokio::fs::create_dir(&path).await {
+        Ok(()) => {},
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+
+    chown(&path, dir.uid, dir.gid).await
+}
+
+/// Recursively archives `dir` into `builder`, skipping symlinks so an entry
+/// pointing outside the accessible tree is never followed into the archive.
+async fn append_tar_dir<W: tokio::io::AsyncWrite + Send + Unpin>(
+    builder: &mut tokio_tar::Builder<W>,
+    root: &Path,
+    dir: &Path,
+) -> std::io::Result<()> {
+    let mut rd = tokio::fs::read_dir(dir).await?;
+
+    while let Some(entry) = rd.next_entry().await? {
+        let entry_path = entry.path();
+        let relpath = entry_path.strip_prefix(root).unwrap_or(&entry_path);
+        let metadata = entry.metadata().await?;
+
+        if metadata.file_type().is_symlink() {
+            continue;
+        }
+
+        if metadata.is_dir() {
+            builder.append_dir(relpath, &entry_path).await?;
+            Box::pin(append_tar_dir(builder, root, &entry_path)).await?;
+        } else if metadata.is_file() {
+            let mut file = tokio::fs::File::open(&entry_path).await?;
+            builder.append_file(relpath, &mut file).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Streams `root` as a tar archive, encoded on the fly into a bounded
+/// in-memory pipe so no temporary file is created.
+fn tar_stream(root: PathBuf) -> Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin> {
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+
+    tokio::spawn(async move {
+        let mut builder = tokio_tar::Builder::new(writer);
+        if let Err(e) = append_tar_dir(&mut builder, &root, &root).await {
+            log::error!("Error while streaming tar archive of {}: {e}", root.display());
+        }
+        let _ = builder.finish().await;
+    });
+
+    Box::new(reader)
+}
+
 impl Filesystem {
     async fn full_path(&self, user: &User, path: impl AsRef<Path>) -> ResolveRes {
         let path = path.as_ref();
@@ -148,14 +268,75 @@ impl Filesystem {
 
         if full_path.starts_with(&target_path.path) {
             if full_path.ends_with(&virtual_path) || target_path.read_only {
-                ResolveRes::Read(full_path, target_path.path.clone())
+                ResolveRes::Read(full_path, Arc::clone(&target_path))
             } else {
-                ResolveRes::Write(full_path, target_path.path.clone())
+                ResolveRes::Write(full_path, Arc::clone(&target_path))
             }
         } else {
             ResolveRes::Error(Error::from(ErrorKind::PermanentFileNotAvailable))
         }
     }
+
+    /// Computes (or returns a cached) digest of the file `path` resolves to.
+    pub async fn checksum(
+        &self,
+        user: &User,
+        path: impl AsRef<Path>,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<String> {
+        let full_path = self.full_path(user, path).await.read_ok()?;
+
+        let fs_meta = tokio::fs::metadata(&full_path)
+            .await
+            .map_err(|_| Error::from(ErrorKind::PermanentFileNotAvailable))?;
+        let size = fs_meta.len();
+        let modified = fs_meta.modified().map_err(|e| Error::new(ErrorKind::LocalError, e))?;
+
+        let canonical = {
+            let full_path = full_path.clone();
+            tokio::task::spawn_blocking(move || canonicalize(full_path))
+                .await
+                .map_err(|e| Error::new(ErrorKind::LocalError, e))??
+        };
+
+        let key: ChecksumKey = (canonical, size, modified, algorithm);
+        if let Some(cached) = self.checksums.get(&key) {
+            return Ok(cached.clone());
+        }
+
+        let digest = hash_file(&full_path, algorithm).await?;
+        self.checksums.retain(|(p, _, _, a), _| !(p == &key.0 && *a == algorithm));
+        self.checksums.insert(key, digest.clone());
+        Ok(digest)
+    }
+}
+
+const DIGEST_CHUNK_SIZE: usize = 8192;
+
+/// Streams `path` through `algorithm`'s digest in fixed-size chunks rather
+/// than reading the whole file into memory at once.
+pub(crate) async fn hash_file(path: &Path, algorithm: ChecksumAlgorithm) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0u8; DIGEST_CHUNK_SIZE];
+
+    macro_rules! digest {
+        ($hasher:expr) => {{
+            let mut hasher = $hasher;
+            loop {
+                let read = file.read(&mut buf).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+            }
+            format!("{:x}", hasher.finalize())
+        }};
+    }
+
+    Ok(match algorithm {
+        ChecksumAlgorithm::Md5 => digest!(Md5::new()),
+        ChecksumAlgorithm::Sha3_256 => digest!(Sha3_256::new()),
+    })
 }
 
 #[async_trait]
@@ -166,18 +347,45 @@ impl StorageBackend<User> for Filesystem {
         libunftp::storage::FEATURE_RESTART | libunftp::storage::FEATURE_SITEMD5
     }
 
+    #[tracing_attributes::instrument]
+    async fn md5<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+    ) -> Result<String> {
+        self.checksum(user, path, ChecksumAlgorithm::Md5).await
+    }
+
     #[tracing_attributes::instrument]
     async fn metadata<P: AsRef<Path> + Send + Debug>(
         &self,
         user: &User,
         path: P,
     ) -> Result<Self::Metadata> {
+        let path = path.as_ref();
+
+        if let Some(dir_path) = tar_source(path) {
+            if let Ok(dir_full_path) = self.full_path(user, &dir_path).await.read_ok() {
+                if let Ok(fs_meta) = tokio::fs::symlink_metadata(&dir_full_path).await {
+                    if fs_meta.is_dir() {
+                        return Ok(Meta {
+                            inner: fs_meta,
+                            synthetic_tar: true,
+                        });
+                    }
+                }
+            }
+        }
+
         let full_path = self.full_path(user, path).await.read_ok()?;
 
         let fs_meta = tokio::fs::symlink_metadata(full_path)
             .await
             .map_err(|_| Error::from(ErrorKind::PermanentFileNotAvailable))?;
-        Ok(Meta { inner: fs_meta })
+        Ok(Meta {
+            inner: fs_meta,
+            synthetic_tar: false,
+        })
     }
 
     #[allow(clippy::type_complexity)]
@@ -192,14 +400,30 @@ impl StorageBackend<User> for Filesystem {
         <Self as StorageBackend<User>>::Metadata: Metadata,
     {
         let (full_path, prefix) = match self.full_path(user, path).await {
-            ResolveRes::Read(full_path, prefix) => (full_path, prefix),
-            ResolveRes::Write(full_path, prefix) => (full_path, prefix),
+            ResolveRes::Read(full_path, dir) => (full_path, dir.path.clone()),
+            ResolveRes::Write(full_path, dir) => (full_path, dir.path.clone()),
             ResolveRes::Error(e) => return Err(e),
             ResolveRes::Root(paths) => {
                 let mut fis: Vec<Fileinfo<PathBuf, Self::Metadata>> = Vec::new();
                 for (name, real) in paths {
                     let metadata = tokio::fs::symlink_metadata(real.as_path()).await?;
-                    let meta: Self::Metadata = Meta { inner: metadata };
+
+                    if metadata.is_dir() {
+                        let mut tar_name = PathBuf::from(&name).into_os_string();
+                        tar_name.push(TAR_SUFFIX);
+                        fis.push(Fileinfo {
+                            path: PathBuf::from(tar_name),
+                            metadata: Meta {
+                                inner: metadata.clone(),
+                                synthetic_tar: true,
+                            },
+                        });
+                    }
+
+                    let meta: Self::Metadata = Meta {
+                        inner: metadata,
+                        synthetic_tar: false,
+                    };
                     fis.push(Fileinfo {
                         path: PathBuf::from(name),
                         metadata: meta,
@@ -219,7 +443,23 @@ impl StorageBackend<User> for Filesystem {
             let relpath = path.strip_prefix(prefix).unwrap();
             let relpath: PathBuf = PathBuf::from(relpath);
             let metadata = tokio::fs::symlink_metadata(dir_entry.path()).await?;
-            let meta: Self::Metadata = Meta { inner: metadata };
+
+            if metadata.is_dir() {
+                let mut tar_name = relpath.clone().into_os_string();
+                tar_name.push(TAR_SUFFIX);
+                fis.push(Fileinfo {
+                    path: PathBuf::from(tar_name),
+                    metadata: Meta {
+                        inner: metadata.clone(),
+                        synthetic_tar: true,
+                    },
+                });
+            }
+
+            let meta: Self::Metadata = Meta {
+                inner: metadata,
+                synthetic_tar: false,
+            };
             fis.push(Fileinfo {
                 path: relpath,
                 metadata: meta,
@@ -238,6 +478,18 @@ impl StorageBackend<User> for Filesystem {
     ) -> Result<Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>> {
         use tokio::io::AsyncSeekExt;
 
+        let path = path.as_ref();
+
+        if let Some(dir_path) = tar_source(path) {
+            if let Ok(dir_full_path) = self.full_path(user, &dir_path).await.read_ok() {
+                if let Ok(fs_meta) = tokio::fs::symlink_metadata(&dir_full_path).await {
+                    if fs_meta.is_dir() {
+                        return Ok(tar_stream(dir_full_path));
+                    }
+                }
+            }
+        }
+
         let full_path = self.full_path(user, path).await.read_ok()?;
         let mut file = tokio::fs::File::open(full_path).await?;
         if start_pos > 0 {
@@ -259,12 +511,16 @@ impl StorageBackend<User> for Filesystem {
         start_pos: u64,
     ) -> Result<u64> {
         let path = path.as_ref();
-        let full_path = self.full_path(user, path).await.write_ok()?;
+        #[allow(unused_variables)]
+        let (full_path, dir) = self.full_path(user, path).await.write_ok_dir()?;
+
+        #[cfg(unix)]
+        let existed = tokio::fs::try_exists(&full_path).await.unwrap_or(false);
 
         let mut file = tokio::fs::OpenOptions::new()
             .write(true)
             .create(true)
-            .open(full_path)
+            .open(&full_path)
             .await?;
         file.set_len(start_pos).await?;
         file.seek(std::io::SeekFrom::Start(start_pos)).await?;
@@ -273,6 +529,12 @@ impl StorageBackend<User> for Filesystem {
         let mut writer = tokio::io::BufWriter::with_capacity(4096, file);
 
         let bytes_copied = tokio::io::copy(&mut reader, &mut writer).await?;
+
+        #[cfg(unix)]
+        if !existed {
+            chown(&full_path, dir.uid, dir.gid).await?;
+        }
+
         Ok(bytes_copied)
     }
 
@@ -294,9 +556,16 @@ impl StorageBackend<User> for Filesystem {
         user: &User,
         path: P,
     ) -> Result<()> {
-        tokio::fs::create_dir(self.full_path(user, path).await.write_ok()?)
+        #[allow(unused_variables)]
+        let (full_path, dir) = self.full_path(user, path).await.write_ok_dir()?;
+
+        #[cfg(unix)]
+        return create_owned(full_path, &dir).await;
+
+        #[cfg(not(unix))]
+        return tokio::fs::create_dir(full_path)
             .await
-            .map_err(|error: std::io::Error| error.into())
+            .map_err(|error: std::io::Error| error.into());
     }
 
     #[tracing_attributes::instrument]
@@ -307,9 +576,12 @@ impl StorageBackend<User> for Filesystem {
         to: P,
     ) -> Result<()> {
         let from = self.full_path(user, from).await.write_ok()?;
-        let to = self.full_path(user, to).await.write_ok()?;
+        #[allow(unused_variables)]
+        let (to, to_dir) = self.full_path(user, to).await.write_ok_dir()?;
 
         let from_rename = from.clone();
+        #[cfg(unix)]
+        let to_chown = to.clone();
 
         let r = tokio::fs::symlink_metadata(from).await;
         match r {
@@ -317,7 +589,12 @@ impl StorageBackend<User> for Filesystem {
                 if metadata.is_file() || metadata.is_dir() {
                     let r = tokio::fs::rename(from_rename, to).await;
                     match r {
-                        Ok(_) => Ok(()),
+                        Ok(_) => {
+                            #[cfg(unix)]
+                            chown(&to_chown, to_dir.uid, to_dir.gid).await?;
+
+                            Ok(())
+                        },
                         Err(e) =>
                             Err(Error::new(ErrorKind::PermanentFileNotAvailable, e)),
                     }
@@ -355,13 +632,17 @@ impl StorageBackend<User> for Filesystem {
 }
 
 impl Metadata for Meta {
-    fn len(&self) -> u64 { self.inner.len() }
+    fn len(&self) -> u64 {
+        if self.synthetic_tar { 0 } else { self.inner.len() }
+    }
 
-    fn is_dir(&self) -> bool { self.inner.is_dir() }
+    fn is_dir(&self) -> bool { !self.synthetic_tar && self.inner.is_dir() }
 
-    fn is_file(&self) -> bool { self.inner.is_file() }
+    fn is_file(&self) -> bool { self.synthetic_tar || self.inner.is_file() }
 
-    fn is_symlink(&self) -> bool { self.inner.file_type().is_symlink() }
+    fn is_symlink(&self) -> bool {
+        !self.synthetic_tar && self.inner.file_type().is_symlink()
+    }
 
     fn modified(&self) -> Result<SystemTime> {
         self.inner.modified().map_err(|e| e.into())
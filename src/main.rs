@@ -7,13 +7,20 @@ mod handler;
 mod user;
 
 pub use std::error::Error as StdError;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
 use libunftp::Server;
 use sha3::{Digest, Sha3_256};
 
 use crate::{
-    auth::Authenticator,
+    auth::{Authenticator, SystemAuthenticator},
+    config::{AuthBackend, TlsConfig},
     handler::Filesystem,
     user::{User, UserMap},
 };
@@ -52,29 +59,84 @@ fn main() -> Result<(), BoxedStdError> {
         return Ok(());
     }
 
-    let users = config::load(
-        std::env::var("FTP_CONFIG").unwrap_or(String::from("./config.yaml")),
-    )?;
+    if std::env::args().nth(1).is_some_and(|s| s == "checksum") {
+        let path: String = args.collect::<Vec<String>>().join(" ");
+
+        if path.is_empty() {
+            return Err("Error: no file provided".into());
+        }
+
+        let rt = tokio::runtime::Runtime::new()?;
+        let digest = rt.block_on(handler::hash_file(
+            Path::new(&path),
+            handler::ChecksumAlgorithm::Sha3_256,
+        ))?;
+
+        println!("SHA3-256 is {digest}");
+
+        return Ok(());
+    }
+
+    let config_path: PathBuf = std::env::var("FTP_CONFIG")
+        .unwrap_or(String::from("./config.yaml"))
+        .into();
+
+    let config = config::load(&config_path)?;
 
     pretty_env_logger::init();
 
     let rt = tokio::runtime::Runtime::new()?;
 
-    rt.block_on(run(Arc::new(users)))?;
+    rt.block_on(run(
+        Arc::new(ArcSwap::from_pointee(config.users)),
+        config.auth,
+        config.tls,
+        config_path,
+    ))?;
 
     Ok(())
 }
 
-async fn run(users: Arc<UserMap>) -> Result<(), BoxedStdError> {
-    let server: Server<Filesystem, User> = Server::with_authenticator(
-        Box::new(move || Filesystem),
-        Arc::new(Authenticator {
-            users: Arc::clone(&users),
-        }),
-    )
+async fn run(
+    users: Arc<ArcSwap<UserMap>>,
+    auth: AuthBackend,
+    tls: Option<TlsConfig>,
+    config_path: PathBuf,
+) -> Result<(), BoxedStdError> {
+    let checksums = Arc::new(DashMap::new());
+
+    let mut server: Server<Filesystem, User> = match auth {
+        AuthBackend::Yaml => {
+            tokio::spawn(config::watch(config_path, Arc::clone(&users)));
+
+            Server::with_authenticator(
+                Box::new(move || Filesystem::new(Arc::clone(&checksums))),
+                Arc::new(Authenticator { users }),
+            )
+        },
+        AuthBackend::System => Server::with_authenticator(
+            Box::new(move || Filesystem::new(Arc::clone(&checksums))),
+            Arc::new(SystemAuthenticator),
+        ),
+    }
     .greeting("Welcome to my FTP server")
     .passive_ports(60000..65535);
 
+    if let Some(tls) = tls {
+        server = server
+            .ftps(tls.cert, tls.key)
+            .ftps_required(if tls.required {
+                libunftp::options::FtpsRequired::All
+            } else {
+                libunftp::options::FtpsRequired::None
+            })
+            .ftps_client_auth(if tls.client_cert_required {
+                libunftp::options::FtpsClientAuth::Require
+            } else {
+                libunftp::options::FtpsClientAuth::Request
+            });
+    }
+
     let addr: SocketAddr = SocketAddr::from(([0, 0, 0, 0], 2121));
 
     eprintln!("Starting on {addr}");
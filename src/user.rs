@@ -6,12 +6,20 @@ use std::{
 };
 
 use libunftp::auth::UserDetail;
+use serde::Deserialize;
 
-#[derive(Debug, Clone)]
+use crate::auth::ClientCertCredential;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "RawVirtualDir")]
 pub struct VirtualDir {
     pub name: String,
     pub path: PathBuf,
     pub read_only: bool,
+    /// Owning uid/gid newly created files and directories are `chown`ed to.
+    /// Only enforced on Unix.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
 }
 
 impl VirtualDir {
@@ -20,15 +28,19 @@ impl VirtualDir {
             name: None,
             path: None,
             read_only: false,
+            uid: None,
+            gid: None,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "RawUser")]
 pub struct User {
     access: HashMap<String, Arc<VirtualDir>>,
     name: String,
-    password: String,
+    password: Option<String>,
+    cert: Option<ClientCertCredential>,
 }
 
 impl User {
@@ -37,6 +49,7 @@ impl User {
             access: Vec::new(),
             name: None,
             password: None,
+            cert: None,
         }
     }
 
@@ -44,6 +57,8 @@ impl User {
         self.access.get(name).map(Arc::clone)
     }
 
+    pub fn name_cloned(&self) -> String { self.name.clone() }
+
     pub fn accesses(&self) -> Vec<(String, PathBuf)> {
         self.access
             .iter()
@@ -52,7 +67,11 @@ impl User {
     }
 
     pub fn password_ok(&self, password: &str) -> bool {
-        self.password.trim() == password.trim()
+        self.password.as_deref().is_some_and(|p| p.trim() == password.trim())
+    }
+
+    pub fn cn_ok(&self, cn: &str) -> bool {
+        self.cert.as_ref().is_some_and(|c| c.allowed_cn == cn)
     }
 }
 
@@ -68,6 +87,7 @@ pub struct UserBuilder {
     access: Vec<VirtualDir>,
     name: Option<String>,
     password: Option<String>,
+    cert: Option<ClientCertCredential>,
 }
 
 impl UserBuilder {
@@ -86,6 +106,13 @@ impl UserBuilder {
         self
     }
 
+    pub fn allowed_cn(mut self, allowed_cn: impl Into<String>) -> Self {
+        self.cert = Some(ClientCertCredential {
+            allowed_cn: allowed_cn.into(),
+        });
+        self
+    }
+
     pub fn build(self) -> User {
         User {
             access: HashMap::from_iter(
@@ -94,7 +121,8 @@ impl UserBuilder {
                     .map(|a| (a.name.clone(), Arc::new(a))),
             ),
             name: self.name.expect("No name provided!"),
-            password: self.password.expect("No password provided!"),
+            password: self.password,
+            cert: self.cert,
         }
     }
 }
@@ -103,6 +131,8 @@ pub struct VirtualDirBuilder {
     name: Option<String>,
     path: Option<PathBuf>,
     read_only: bool,
+    uid: Option<u32>,
+    gid: Option<u32>,
 }
 
 impl VirtualDirBuilder {
@@ -111,6 +141,8 @@ impl VirtualDirBuilder {
             name: self.name.expect("No name provided!"),
             path: self.path.expect("No path provided!"),
             read_only: self.read_only,
+            uid: self.uid,
+            gid: self.gid,
         }
     }
 
@@ -128,6 +160,16 @@ impl VirtualDirBuilder {
         self.read_only = true;
         self
     }
+
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
 }
 
 pub struct UsersBuilder {
@@ -148,3 +190,62 @@ impl UsersBuilder {
 }
 
 impl UserDetail for User {}
+
+/// On-disk shape of a user entry in `config.yaml`, converted into a `User`
+/// through the builder rather than derived directly, since `User::access` is
+/// internally a `HashMap<String, Arc<VirtualDir>>`.
+#[derive(Deserialize)]
+struct RawUser {
+    name: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    allowed_cn: Option<String>,
+    #[serde(default)]
+    access: Vec<VirtualDir>,
+}
+
+impl From<RawUser> for User {
+    fn from(raw: RawUser) -> Self {
+        let mut builder = User::builder().name(raw.name);
+        if let Some(password) = raw.password {
+            builder = builder.password(password);
+        }
+        if let Some(allowed_cn) = raw.allowed_cn {
+            builder = builder.allowed_cn(allowed_cn);
+        }
+        for dir in raw.access {
+            builder = builder.access(dir);
+        }
+        builder.build()
+    }
+}
+
+/// On-disk shape of a `VirtualDir` grant, converted through the builder.
+#[derive(Deserialize)]
+struct RawVirtualDir {
+    name: String,
+    path: PathBuf,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    uid: Option<u32>,
+    #[serde(default)]
+    gid: Option<u32>,
+}
+
+impl From<RawVirtualDir> for VirtualDir {
+    fn from(raw: RawVirtualDir) -> Self {
+        let mut builder = VirtualDir::builder().name(raw.name).path(raw.path);
+        if raw.read_only {
+            builder = builder.read_only();
+        }
+        if let Some(uid) = raw.uid {
+            builder = builder.uid(uid);
+        }
+        if let Some(gid) = raw.gid {
+            builder = builder.gid(gid);
+        }
+        builder.build()
+    }
+}